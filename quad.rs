@@ -2,6 +2,9 @@
 
 use std::num::FromPrimitive;
 use std::default::Default;
+use std::collections::BinaryHeap;
+use std::cmp::Ordering;
+use std::mem;
 
 trait QTNumber: Num + Ord + FromPrimitive + Copy + Default {}
 
@@ -10,6 +13,15 @@ trait Point<N> {
     fn y(&self)-> N;
 }
 
+// Something that occupies an area rather than a single coordinate. Plain
+// points implement this with a zero-width box at their own location; the
+// tree uses it (rather than `Point::x`/`Point::y`) to decide how deep an
+// item can be pushed down the quadrants during insertion and removal.
+trait Bounded<N> {
+    fn bounds(&self) -> AABB<N>;
+}
+
+#[deriving(Copy)]
 struct Cardinal<T> {
     nw: T,
     ne: T,
@@ -57,110 +69,653 @@ impl <N: QTNumber, P: Point<N>> AABB<N> {
     }
 }
 
-enum QuadTree<N, P> {
+impl <N: QTNumber> AABB<N> {
+    fn intersects(&self, other: &AABB<N>) -> bool {
+        if self.x + self.w < other.x { return false }
+        if other.x + other.w < self.x { return false }
+        if self.y + self.w < other.y { return false }
+        if other.y + other.w < self.y { return false }
+
+        return true;
+    }
+
+    fn contains_box(&self, other: &AABB<N>) -> bool {
+        if other.x < self.x { return false }
+        if other.y < self.y { return false }
+        if other.x + other.w > self.x + self.w { return false }
+        if other.y + other.w > self.y + self.w { return false }
+
+        return true;
+    }
+}
+
+impl <N: QTNumber, P: Point<N>> AABB<N> {
+    // Squared distance from `pt` to the nearest point of this box (0 when
+    // `pt` is inside). Used as a lower bound on the distance to anything
+    // stored in the subtree this box covers.
+    fn dist_sq(&self, pt: &P) -> N {
+        let zero: N = Default::default();
+        let px = pt.x();
+        let py = pt.y();
+
+        let dx = if px < self.x { self.x - px }
+                 else if px > self.x + self.w { px - (self.x + self.w) }
+                 else { zero };
+        let dy = if py < self.y { self.y - py }
+                 else if py > self.y + self.w { py - (self.y + self.w) }
+                 else { zero };
+
+        dx * dx + dy * dy
+    }
+}
+
+// A subtree (by node handle) still waiting to be explored, ordered so that
+// the smallest `dist` (the lower bound to the search target) is popped
+// first from a `BinaryHeap`.
+struct PendingNode<N> {
+    dist: N,
+    idx: uint
+}
+impl <N: QTNumber> PartialEq for PendingNode<N> {
+    fn eq(&self, other: &PendingNode<N>) -> bool { self.dist == other.dist }
+}
+impl <N: QTNumber> Eq for PendingNode<N> {}
+impl <N: QTNumber> PartialOrd for PendingNode<N> {
+    fn partial_cmp(&self, other: &PendingNode<N>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl <N: QTNumber> Ord for PendingNode<N> {
+    fn cmp(&self, other: &PendingNode<N>) -> Ordering {
+        // Reversed so `BinaryHeap` (a max-heap) yields the smallest `dist` first.
+        other.dist.cmp(&self.dist)
+    }
+}
+
+// A candidate point kept in the bounded best-k max-heap; the heap's top is
+// always the current worst of the k best so it can be evicted cheaply.
+struct Candidate<'a, N: 'a, P: 'a, V: 'a> {
+    dist: N,
+    p: &'a P,
+    v: &'a V
+}
+impl <'a, N: QTNumber, P, V> PartialEq for Candidate<'a, N, P, V> {
+    fn eq(&self, other: &Candidate<'a, N, P, V>) -> bool { self.dist == other.dist }
+}
+impl <'a, N: QTNumber, P, V> Eq for Candidate<'a, N, P, V> {}
+impl <'a, N: QTNumber, P, V> PartialOrd for Candidate<'a, N, P, V> {
+    fn partial_cmp(&self, other: &Candidate<'a, N, P, V>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl <'a, N: QTNumber, P, V> Ord for Candidate<'a, N, P, V> {
+    fn cmp(&self, other: &Candidate<'a, N, P, V>) -> Ordering {
+        self.dist.cmp(&other.dist)
+    }
+}
+
+// A single slot in the arena. `Node` children are indices back into the
+// owning `QuadTree`'s `nodes` vector rather than boxed subtrees.
+enum QuadNode<N, P, V> {
     Leaf {
         bounding: AABB<N>,
-        contents: Vec<P>,
+        contents: Vec<(P, V)>,
         cutoff: uint
-    }, // One value
+    }, // Points paired with their associated value
     Node {
         bounding: AABB<N>, // Split into quadrants
-        children: Box<Cardinal<QuadTree<N, P>>>,
+        children: Cardinal<uint>,
+        // Items whose bounds straddle more than one child quadrant and so
+        // cannot be pushed down any further; see `QuadTree::child_for_bounds`.
+        overflow: Vec<(P, V)>,
         cutoff: uint
     }
 }
-impl <N: QTNumber, P> QuadTree<N, P> {
-    fn leaf_empty(bounding: AABB<N>, cutoff: uint) -> QuadTree<N, P>{
+impl <N: QTNumber, P, V> QuadNode<N, P, V> {
+    fn leaf_empty(bounding: AABB<N>, cutoff: uint) -> QuadNode<N, P, V>{
         Leaf {
             bounding: bounding,
             contents: Vec::with_capacity(cutoff),
             cutoff: cutoff
         }
     }
-    fn leaf(bounding: AABB<N>, contents: Vec<P>, cutoff: uint) -> QuadTree<N, P> {
+    fn leaf(bounding: AABB<N>, contents: Vec<(P, V)>, cutoff: uint) -> QuadNode<N, P, V> {
         Leaf {
             bounding: bounding,
             contents: contents,
             cutoff: cutoff
         }
     }
-    fn node(bounding: AABB<N>, children: Box<Cardinal<QuadTree<N, P>>>, cutoff: uint) -> QuadTree<N, P>{
+    fn node(bounding: AABB<N>, children: Cardinal<uint>, cutoff: uint) -> QuadNode<N, P, V>{
         Node {
             bounding: bounding,
             children: children,
+            overflow: Vec::new(),
             cutoff: cutoff
         }
     }
-    pub fn new(bounding: AABB<N>, cutoff: uint) -> QuadTree<N, P> {
+}
+
+// The arena: every node lives in `nodes`, addressed by index, so subtrees
+// are cheap `uint` handles instead of separately-allocated boxes. `free`
+// lists slots vacated by `try_collapse_at` so `breakup_at` can reuse them
+// instead of growing `nodes` without bound under repeated split/collapse.
+struct QuadTree<N, P, V> {
+    nodes: Vec<QuadNode<N, P, V>>,
+    free: Vec<uint>,
+    root: uint
+}
+impl <N: QTNumber, P, V> QuadTree<N, P, V> {
+    pub fn new(bounding: AABB<N>, cutoff: uint) -> QuadTree<N, P, V> {
         let split = bounding.split();
-        QuadTree::node(bounding, box Cardinal::new(
-                QuadTree::leaf_empty(split.nw, cutoff),
-                QuadTree::leaf_empty(split.ne, cutoff),
-                QuadTree::leaf_empty(split.sw, cutoff),
-                QuadTree::leaf_empty(split.se, cutoff)), cutoff)
+        let mut nodes = Vec::new();
+        nodes.push(QuadNode::leaf_empty(split.nw, cutoff));
+        nodes.push(QuadNode::leaf_empty(split.ne, cutoff));
+        nodes.push(QuadNode::leaf_empty(split.sw, cutoff));
+        nodes.push(QuadNode::leaf_empty(split.se, cutoff));
+        let children = Cardinal::new(0u, 1u, 2u, 3u);
+        nodes.push(QuadNode::node(bounding, children, cutoff));
+        let root = nodes.len() - 1;
+        QuadTree { nodes: nodes, free: Vec::new(), root: root }
     }
 }
 
-impl <N: QTNumber, P: Point<N> + Clone> QuadTree<N, P> {
-    fn contains(&self, p: &P) -> bool {
-        match self {
-            &Leaf{ref bounding, ref contents, ref cutoff } => bounding.contains(p),
-            &Node{ref bounding, ref children, ref cutoff } => bounding.contains(p),
+impl <N: QTNumber, P: Point<N> + Bounded<N> + Clone + PartialEq, V: Clone> QuadTree<N, P, V> {
+    fn bounding_at(&self, idx: uint) -> AABB<N> {
+        match &self.nodes[idx] {
+            &Leaf { ref bounding, .. } => *bounding,
+            &Node { ref bounding, .. } => *bounding
+        }
+    }
+
+    fn is_leaf_at(&self, idx: uint) -> bool {
+        match &self.nodes[idx] {
+            &Leaf { .. } => true,
+            &Node { .. } => false
+        }
+    }
+
+    fn is_full_at(&self, idx: uint) -> bool {
+        match &self.nodes[idx] {
+            &Node { .. } => false,
+            &Leaf { ref contents, cutoff, .. } => contents.len() == cutoff
         }
     }
 
-    fn is_full(&self) -> bool {
-        match self {
-            &Node { ref bounding, ref children, ref cutoff } => false,
-            &Leaf { ref bounding, ref contents, ref cutoff } => contents.len() == *cutoff
+    fn count_at(&self, idx: uint) -> uint {
+        match &self.nodes[idx] {
+            &Leaf { ref contents, .. } => contents.len(),
+            &Node { ref children, ref overflow, .. } => {
+                self.count_at(children.nw) + self.count_at(children.ne) +
+                    self.count_at(children.sw) + self.count_at(children.se) + overflow.len()
+            }
+        }
+    }
+
+    fn collect_from(&self, idx: uint, out: &mut Vec<(P, V)>) {
+        match &self.nodes[idx] {
+            &Leaf { ref contents, .. } => {
+                for pair in contents.iter() {
+                    let &(ref p, ref v) = pair;
+                    out.push((p.clone(), v.clone()));
+                }
+            }
+            &Node { .. } => fail!("internal error: collect_from() on Node")
+        }
+    }
+
+    // Reuses a slot freed by `try_collapse_at` when one is available, so the
+    // arena doesn't grow without bound under repeated split/collapse cycles.
+    fn alloc_node(&mut self, node: QuadNode<N, P, V>) -> uint {
+        match self.free.pop() {
+            Some(idx) => { self.nodes[idx] = node; idx }
+            None => { self.nodes.push(node); self.nodes.len() - 1 }
+        }
+    }
+
+    fn free_node(&mut self, idx: uint) {
+        self.free.push(idx);
+    }
+
+    fn breakup_at(&mut self, idx: uint) {
+        let (bounding, cutoff, contents) = match &mut self.nodes[idx] {
+            &Node { .. } => fail!("internal error: breakup_at() on Node"),
+            &Leaf { bounding, ref mut contents, cutoff } => {
+                (bounding, cutoff, mem::replace(contents, Vec::new()))
+            }
+        };
+
+        let bb = bounding.split();
+        let nw_idx = self.alloc_node(QuadNode::leaf_empty(bb.nw, cutoff));
+        let ne_idx = self.alloc_node(QuadNode::leaf_empty(bb.ne, cutoff));
+        let sw_idx = self.alloc_node(QuadNode::leaf_empty(bb.sw, cutoff));
+        let se_idx = self.alloc_node(QuadNode::leaf_empty(bb.se, cutoff));
+
+        self.nodes[idx] = QuadNode::node(bounding, Cardinal::new(nw_idx, ne_idx, sw_idx, se_idx), cutoff);
+
+        for pair in contents.into_iter() {
+            let (p, v) = pair;
+            self.insert_at(idx, p, v);
+        }
+    }
+
+    fn query(&self, area: &AABB<N>) -> Vec<(&P, &V)> {
+        let mut out = Vec::new();
+        self.query_into(self.root, area, &mut out);
+        out
+    }
+
+    fn query_into<'a>(&'a self, idx: uint, area: &AABB<N>, out: &mut Vec<(&'a P, &'a V)>) {
+        match &self.nodes[idx] {
+            &Leaf { ref bounding, ref contents, .. } => {
+                if !bounding.intersects(area) { return; }
+                for pair in contents.iter() {
+                    let &(ref p, ref v) = pair;
+                    if area.contains(p) {
+                        out.push((p, v));
+                    }
+                }
+            }
+            &Node { ref bounding, ref children, ref overflow, .. } => {
+                if !bounding.intersects(area) { return; }
+                for pair in overflow.iter() {
+                    let &(ref p, ref v) = pair;
+                    if area.intersects(&p.bounds()) {
+                        out.push((p, v));
+                    }
+                }
+                self.query_into(children.nw, area, out);
+                self.query_into(children.ne, area, out);
+                self.query_into(children.sw, area, out);
+                self.query_into(children.se, area, out);
+            }
         }
     }
 
-    fn breakup(&mut self) -> QuadTree<N, P> {
-        match self {
-            &Node {ref bounding, ref children, ref cutoff } => fail!("internal error: breakup() on Node"),
-            &Leaf {ref bounding, ref mut contents, cutoff } => {
-                let bb = bounding.split();
-                let mut node = QuadTree::node(*bounding, box Cardinal::new(
-                        QuadTree::leaf_empty(bb.nw, cutoff), QuadTree::leaf_empty(bb.ne, cutoff),
-                        QuadTree::leaf_empty(bb.sw, cutoff), QuadTree::leaf_empty(bb.se, cutoff)), cutoff);
+    // Like `query`, but whole leaves that are fully covered by `area` are
+    // bulk-collected without per-point `contains` checks.
+    fn query_strict(&self, area: &AABB<N>) -> Vec<(&P, &V)> {
+        let mut out = Vec::new();
+        self.query_strict_into(self.root, area, &mut out);
+        out
+    }
 
-                for point in contents.iter() {
-                    node.insert(point.clone());
+    fn query_strict_into<'a>(&'a self, idx: uint, area: &AABB<N>, out: &mut Vec<(&'a P, &'a V)>) {
+        match &self.nodes[idx] {
+            &Leaf { ref bounding, ref contents, .. } => {
+                if !bounding.intersects(area) { return; }
+                if area.contains_box(bounding) {
+                    for pair in contents.iter() {
+                        let &(ref p, ref v) = pair;
+                        out.push((p, v));
+                    }
+                    return;
                 }
+                for pair in contents.iter() {
+                    let &(ref p, ref v) = pair;
+                    if area.contains(p) {
+                        out.push((p, v));
+                    }
+                }
+            }
+            &Node { ref bounding, ref children, ref overflow, .. } => {
+                if !bounding.intersects(area) { return; }
+                for pair in overflow.iter() {
+                    let &(ref p, ref v) = pair;
+                    if area.intersects(&p.bounds()) {
+                        out.push((p, v));
+                    }
+                }
+                self.query_strict_into(children.nw, area, out);
+                self.query_strict_into(children.ne, area, out);
+                self.query_strict_into(children.sw, area, out);
+                self.query_strict_into(children.se, area, out);
+            }
+        }
+    }
+
+    // Returns the child of `idx` (a `Node`) whose box fully contains `b`, or
+    // `None` when `b` straddles more than one quadrant and must stay in
+    // `idx`'s own overflow list instead.
+    fn child_for_bounds(&self, idx: uint, b: &AABB<N>) -> Option<uint> {
+        let children = match &self.nodes[idx] {
+            &Node { ref children, .. } => *children,
+            &Leaf { .. } => fail!("internal error: child_for_bounds() on Leaf")
+        };
+        if self.bounding_at(children.nw).contains_box(b) { return Some(children.nw); }
+        if self.bounding_at(children.ne).contains_box(b) { return Some(children.ne); }
+        if self.bounding_at(children.sw).contains_box(b) { return Some(children.sw); }
+        if self.bounding_at(children.se).contains_box(b) { return Some(children.se); }
+        None
+    }
 
-                node
+    fn insert_at(&mut self, idx: uint, p: P, v: V) -> bool {
+        let b = p.bounds();
+        if !self.bounding_at(idx).contains_box(&b) {
+            return false;
+        }
+        if self.is_leaf_at(idx) {
+            match &mut self.nodes[idx] {
+                &Leaf { ref mut contents, .. } => { contents.push((p, v)); }
+                &Node { .. } => fail!("internal error: insert_at() leaf branch on Node")
+            }
+        } else {
+            match self.child_for_bounds(idx, &b) {
+                Some(child) => {
+                    if !self.is_full_at(child) {
+                        self.insert_at(child, p, v);
+                    } else {
+                        self.breakup_at(child);
+                        self.insert_at(child, p, v);
+                    }
+                }
+                None => {
+                    match &mut self.nodes[idx] {
+                        &Node { ref mut overflow, .. } => { overflow.push((p, v)); }
+                        &Leaf { .. } => fail!("internal error: insert_at() overflow branch on Leaf")
+                    }
+                }
             }
         }
+        return true;
+    }
+
+    fn insert(&mut self, p: P, v: V) -> bool {
+        let root = self.root;
+        self.insert_at(root, p, v)
     }
 
-    fn insert(&mut self, p: P) -> bool {
-        if !self.contains(&p) {
+    fn remove_at(&mut self, idx: uint, p: &P) -> bool {
+        let b = p.bounds();
+        if !self.bounding_at(idx).contains_box(&b) {
             return false;
         }
-        match self {
-            &Leaf{ ref bounding, ref mut contents, cutoff } => {
-                contents.push(p);
+        let removed = if self.is_leaf_at(idx) {
+            match &mut self.nodes[idx] {
+                &Leaf { ref mut contents, .. } => {
+                    let pos = contents.iter().position(|pair| {
+                        let &(ref ep, _) = pair;
+                        ep == p
+                    });
+                    match pos {
+                        Some(i) => { contents.remove(i); true }
+                        None => false
+                    }
+                }
+                &Node { .. } => fail!("internal error: remove_at() leaf branch on Node")
             }
-            &Node{ ref bounding, ref mut children, cutoff } => {
-                let mut rep = &mut children.se;
-                if children.nw.contains(&p) {
-                    rep = &mut children.nw;
-                } else if children.ne.contains(&p) {
-                    rep = &mut children.ne;
-                } else if children.sw.contains(&p) {
-                    rep = &mut children.sw;
+        } else {
+            let removed_from_overflow = match &mut self.nodes[idx] {
+                &Node { ref mut overflow, .. } => {
+                    let pos = overflow.iter().position(|pair| {
+                        let &(ref ep, _) = pair;
+                        ep == p
+                    });
+                    match pos {
+                        Some(i) => { overflow.remove(i); true }
+                        None => false
+                    }
+                }
+                &Leaf { .. } => fail!("internal error: remove_at() overflow branch on Leaf")
+            };
+            if removed_from_overflow {
+                true
+            } else {
+                match self.child_for_bounds(idx, &b) {
+                    Some(child) => self.remove_at(child, p),
+                    None => false
                 }
-                assert!(rep.contains(&p), "{:?}, {:?}", rep, p);
-                if !rep.is_full() {
-                    rep.insert(p);
+            }
+        };
+        if removed {
+            self.try_collapse_at(idx);
+        }
+        return removed;
+    }
+
+    fn remove(&mut self, p: &P) -> bool {
+        let root = self.root;
+        self.remove_at(root, p)
+    }
+
+    // Replaces a `Node` whose four children are all leaves with a single
+    // merged `Leaf`, once their combined contents fit back under `cutoff`.
+    // The inverse of `breakup_at`. Called after every successful `remove_at`
+    // so a cascade of deletions collapses the tree level by level.
+    fn try_collapse_at(&mut self, idx: uint) {
+        let merged = match &self.nodes[idx] {
+            &Node { bounding, ref children, ref overflow, cutoff } => {
+                let all_leaves = self.is_leaf_at(children.nw) && self.is_leaf_at(children.ne) &&
+                    self.is_leaf_at(children.sw) && self.is_leaf_at(children.se);
+                let total = self.count_at(children.nw) + self.count_at(children.ne) +
+                    self.count_at(children.sw) + self.count_at(children.se) + overflow.len();
+                if all_leaves && total <= cutoff {
+                    let mut contents = Vec::with_capacity(cutoff);
+                    self.collect_from(children.nw, &mut contents);
+                    self.collect_from(children.ne, &mut contents);
+                    self.collect_from(children.sw, &mut contents);
+                    self.collect_from(children.se, &mut contents);
+                    for pair in overflow.iter() {
+                        let &(ref p, ref v) = pair;
+                        contents.push((p.clone(), v.clone()));
+                    }
+                    Some((QuadNode::leaf(bounding, contents, cutoff), *children))
                 } else {
-                    *rep = rep.breakup();
-                    rep.insert(p);
+                    None
                 }
             }
+            &Leaf { .. } => None
         };
-        return true;
+        match merged {
+            Some((leaf, children)) => {
+                self.nodes[idx] = leaf;
+                // The four leaf children are now unreachable; hand their
+                // slots back to `alloc_node` instead of leaving them as
+                // permanent garbage in `nodes`.
+                self.free_node(children.nw);
+                self.free_node(children.ne);
+                self.free_node(children.sw);
+                self.free_node(children.se);
+            }
+            None => {}
+        }
+    }
+
+    fn count(&self) -> uint {
+        self.count_at(self.root)
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.is_leaf_at(self.root)
+    }
+
+    // A cursor onto a single node of the arena, for callers that want to walk
+    // the structure directly (visibility queries, LOD culling, debug
+    // visualization) instead of only through the monolithic methods above.
+    fn node<'a>(&'a self, idx: uint) -> NodeRef<'a, N, P, V> {
+        NodeRef { tree: self, idx: idx }
+    }
+
+    pub fn root<'a>(&'a self) -> NodeRef<'a, N, P, V> {
+        let root = self.root;
+        self.node(root)
+    }
+
+    pub fn root_mut<'a>(&'a mut self) -> NodeRefMut<'a, N, P, V> {
+        let root = self.root;
+        NodeRefMut { tree: self, idx: root }
+    }
+
+    fn force_split_at(&mut self, idx: uint) {
+        if !self.is_leaf_at(idx) { return; }
+        self.breakup_at(idx);
+    }
+
+    // Exact k-nearest-neighbor search via best-first traversal: subtrees are
+    // explored closest-lower-bound-first, and the search stops as soon as the
+    // closest remaining subtree is already farther than the k-th best point
+    // found so far.
+    fn nearest<'a>(&'a self, target: &P, k: uint) -> Vec<&'a P> {
+        if k == 0 { return Vec::new(); }
+
+        let mut pending = BinaryHeap::new();
+        pending.push(PendingNode { dist: self.bounding_at(self.root).dist_sq(target), idx: self.root });
+
+        let mut best = BinaryHeap::new();
+
+        while let Some(PendingNode { dist, idx }) = pending.pop() {
+            if best.len() == k {
+                let worse_than_best = {
+                    let worst: &Candidate<N, P, V> = best.peek().unwrap();
+                    dist > worst.dist
+                };
+                if worse_than_best {
+                    break;
+                }
+            }
+
+            match &self.nodes[idx] {
+                &Leaf { ref contents, .. } => {
+                    for pair in contents.iter() {
+                        let &(ref p, ref v) = pair;
+                        let d = p.bounds().dist_sq(target);
+                        if best.len() < k {
+                            best.push(Candidate { dist: d, p: p, v: v });
+                        } else {
+                            let should_replace = {
+                                let worst: &Candidate<N, P, V> = best.peek().unwrap();
+                                d < worst.dist
+                            };
+                            if should_replace {
+                                best.pop();
+                                best.push(Candidate { dist: d, p: p, v: v });
+                            }
+                        }
+                    }
+                }
+                &Node { ref children, ref overflow, .. } => {
+                    // Overflow items straddle quadrant boundaries and so have
+                    // no subtree of their own to enqueue; test them directly
+                    // against the candidate heap, same as leaf contents.
+                    for pair in overflow.iter() {
+                        let &(ref p, ref v) = pair;
+                        let d = p.bounds().dist_sq(target);
+                        if best.len() < k {
+                            best.push(Candidate { dist: d, p: p, v: v });
+                        } else {
+                            let should_replace = {
+                                let worst: &Candidate<N, P, V> = best.peek().unwrap();
+                                d < worst.dist
+                            };
+                            if should_replace {
+                                best.pop();
+                                best.push(Candidate { dist: d, p: p, v: v });
+                            }
+                        }
+                    }
+
+                    pending.push(PendingNode { dist: self.bounding_at(children.nw).dist_sq(target), idx: children.nw });
+                    pending.push(PendingNode { dist: self.bounding_at(children.ne).dist_sq(target), idx: children.ne });
+                    pending.push(PendingNode { dist: self.bounding_at(children.sw).dist_sq(target), idx: children.sw });
+                    pending.push(PendingNode { dist: self.bounding_at(children.se).dist_sq(target), idx: children.se });
+                }
+            }
+        }
+
+        let mut found: Vec<Candidate<N, P, V>> = best.into_iter().collect();
+        found.sort_by(|a, b| a.dist.cmp(&b.dist));
+        found.into_iter().map(|c| c.p).collect()
+    }
+}
+
+// A read-only cursor pointing at a single arena node. Callers walk the tree
+// by hand (visibility queries, LOD culling, debug visualization) instead of
+// going through the monolithic `query`/`nearest` methods.
+struct NodeRef<'a, N: 'a, P: 'a, V: 'a> {
+    tree: &'a QuadTree<N, P, V>,
+    idx: uint
+}
+impl <'a, N: QTNumber, P: Point<N> + Bounded<N> + Clone + PartialEq, V: Clone> NodeRef<'a, N, P, V> {
+    pub fn is_leaf(&self) -> bool {
+        self.tree.is_leaf_at(self.idx)
+    }
+
+    pub fn bounding(&self) -> AABB<N> {
+        self.tree.bounding_at(self.idx)
+    }
+
+    pub fn children(&self) -> Option<Cardinal<NodeRef<'a, N, P, V>>> {
+        match &self.tree.nodes[self.idx] {
+            &Node { ref children, .. } => Some(Cardinal::new(
+                self.tree.node(children.nw),
+                self.tree.node(children.ne),
+                self.tree.node(children.sw),
+                self.tree.node(children.se)
+            )),
+            &Leaf { .. } => None
+        }
+    }
+
+    pub fn contents(&self) -> Option<&'a [(P, V)]> {
+        match &self.tree.nodes[self.idx] {
+            &Leaf { ref contents, .. } => Some(contents.as_slice()),
+            &Node { .. } => None
+        }
+    }
+}
+
+// The mutable counterpart of `NodeRef`. It owns the `&mut QuadTree` borrow
+// for its whole lifetime, so unlike `NodeRef::children` it cannot hand out
+// four simultaneous child cursors (that would alias the same `&mut`);
+// instead `child_indices` gives the handles and `into_child` consumes this
+// cursor to move down to one of them.
+struct NodeRefMut<'a, N: 'a, P: 'a, V: 'a> {
+    tree: &'a mut QuadTree<N, P, V>,
+    idx: uint
+}
+impl <'a, N: QTNumber, P: Point<N> + Bounded<N> + Clone + PartialEq, V: Clone> NodeRefMut<'a, N, P, V> {
+    pub fn is_leaf(&self) -> bool {
+        self.tree.is_leaf_at(self.idx)
+    }
+
+    pub fn bounding(&self) -> AABB<N> {
+        self.tree.bounding_at(self.idx)
+    }
+
+    pub fn child_indices(&self) -> Option<Cardinal<uint>> {
+        match &self.tree.nodes[self.idx] {
+            &Node { ref children, .. } => Some(*children),
+            &Leaf { .. } => None
+        }
+    }
+
+    pub fn into_child(self, idx: uint) -> NodeRefMut<'a, N, P, V> {
+        NodeRefMut { tree: self.tree, idx: idx }
+    }
+
+    pub fn contents(&self) -> Option<&[(P, V)]> {
+        match &self.tree.nodes[self.idx] {
+            &Leaf { ref contents, .. } => Some(contents.as_slice()),
+            &Node { .. } => None
+        }
+    }
+
+    pub fn insert_here(&mut self, p: P, v: V) -> bool {
+        let idx = self.idx;
+        self.tree.insert_at(idx, p, v)
+    }
+
+    pub fn remove_here(&mut self, p: &P) -> bool {
+        let idx = self.idx;
+        self.tree.remove_at(idx, p)
+    }
+
+    // Splits this node's leaf into four child leaves and redistributes its
+    // contents among them (a no-op if it is already a `Node`), without
+    // requiring it to be full first.
+    pub fn force_split(&mut self) {
+        let idx = self.idx;
+        self.tree.force_split_at(idx);
     }
 }
 
@@ -176,21 +731,245 @@ fn testBasic() {
         fn x(&self)->f64{ self.x }
         fn y(&self)->f64{ self.y }
     }
+    impl Bounded<f64> for Pt {
+        fn bounds(&self) -> AABB<f64> { AABB::new(self.x, self.y, 0.0) }
+    }
     impl Pt {
         fn new(x: f64, y: f64) -> Pt {
             Pt { x: x, y: y }
         }
     }
 
-    let mut tree:QuadTree<f64, Pt> = QuadTree::new(AABB::new(0.0, 0.0, 100.0), 4);
+    let mut tree:QuadTree<f64, Pt, uint> = QuadTree::new(AABB::new(0.0, 0.0, 100.0), 4);
+
+    tree.insert(Pt::new(0.0, 0.0), 0u);
+    tree.insert(Pt::new(1.0, 1.0), 1u);
+    tree.insert(Pt::new(2.0, 2.0), 2u);
+    tree.insert(Pt::new(3.0, 3.0), 3u);
+    tree.insert(Pt::new(4.0, 4.0), 4u);
+    tree.insert(Pt::new(5.0, 5.0), 5u);
+
+    assert_eq!(tree.count(), 6);
+}
+
+#[test]
+fn testQuery() {
+    #[deriving(Show)]
+    impl QTNumber for f64 {}
+    #[deriving(Clone)]
+    struct Pt { x:f64, y: f64}
+    impl Point<f64> for Pt {
+        fn x(&self)->f64{ self.x }
+        fn y(&self)->f64{ self.y }
+    }
+    impl Bounded<f64> for Pt {
+        fn bounds(&self) -> AABB<f64> { AABB::new(self.x, self.y, 0.0) }
+    }
+    impl Pt {
+        fn new(x: f64, y: f64) -> Pt {
+            Pt { x: x, y: y }
+        }
+    }
+
+    let mut tree:QuadTree<f64, Pt, uint> = QuadTree::new(AABB::new(0.0, 0.0, 100.0), 4);
+
+    tree.insert(Pt::new(0.0, 0.0), 0u);
+    tree.insert(Pt::new(1.0, 1.0), 1u);
+    tree.insert(Pt::new(2.0, 2.0), 2u);
+    tree.insert(Pt::new(50.0, 50.0), 3u);
+    tree.insert(Pt::new(90.0, 90.0), 4u);
+
+    let found = tree.query(&AABB::new(0.0, 0.0, 10.0));
+    assert_eq!(found.len(), 3);
+
+    let found_strict = tree.query_strict(&AABB::new(0.0, 0.0, 100.0));
+    assert_eq!(found_strict.len(), 5);
+}
+
+#[test]
+fn testRemove() {
+    #[deriving(Show)]
+    impl QTNumber for f64 {}
+    #[deriving(Clone, PartialEq)]
+    struct Pt { x:f64, y: f64}
+    impl Point<f64> for Pt {
+        fn x(&self)->f64{ self.x }
+        fn y(&self)->f64{ self.y }
+    }
+    impl Bounded<f64> for Pt {
+        fn bounds(&self) -> AABB<f64> { AABB::new(self.x, self.y, 0.0) }
+    }
+    impl Pt {
+        fn new(x: f64, y: f64) -> Pt {
+            Pt { x: x, y: y }
+        }
+    }
+
+    let mut tree:QuadTree<f64, Pt, uint> = QuadTree::new(AABB::new(0.0, 0.0, 100.0), 4);
+
+    tree.insert(Pt::new(0.0, 0.0), 0u);
+    tree.insert(Pt::new(1.0, 1.0), 1u);
+    tree.insert(Pt::new(2.0, 2.0), 2u);
+    tree.insert(Pt::new(50.0, 50.0), 3u);
+    tree.insert(Pt::new(90.0, 90.0), 4u);
+    tree.insert(Pt::new(91.0, 91.0), 5u);
 
-    tree.insert(Pt::new(0.0, 0.0));
-    tree.insert(Pt::new(1.0, 1.0));
-    tree.insert(Pt::new(2.0, 2.0));
-    tree.insert(Pt::new(3.0, 3.0));
-    tree.insert(Pt::new(4.0, 4.0));
-    tree.insert(Pt::new(5.0, 5.0));
+    assert!(tree.remove(&Pt::new(91.0, 91.0)));
+    assert!(!tree.remove(&Pt::new(91.0, 91.0)));
+    assert_eq!(tree.count(), 5);
 
-    println!("{:?}", tree);
-    assert!(false);
+    assert!(tree.remove(&Pt::new(50.0, 50.0)));
+    assert!(tree.remove(&Pt::new(90.0, 90.0)));
+    assert!(tree.is_leaf());
+    assert_eq!(tree.count(), 3);
+}
+
+#[test]
+fn testNearest() {
+    #[deriving(Show)]
+    impl QTNumber for f64 {}
+    #[deriving(Clone, PartialEq)]
+    struct Pt { x:f64, y: f64}
+    impl Point<f64> for Pt {
+        fn x(&self)->f64{ self.x }
+        fn y(&self)->f64{ self.y }
+    }
+    impl Bounded<f64> for Pt {
+        fn bounds(&self) -> AABB<f64> { AABB::new(self.x, self.y, 0.0) }
+    }
+    impl Pt {
+        fn new(x: f64, y: f64) -> Pt {
+            Pt { x: x, y: y }
+        }
+    }
+
+    let mut tree:QuadTree<f64, Pt, uint> = QuadTree::new(AABB::new(0.0, 0.0, 100.0), 4);
+
+    tree.insert(Pt::new(0.0, 0.0), 0u);
+    tree.insert(Pt::new(1.0, 1.0), 1u);
+    tree.insert(Pt::new(10.0, 10.0), 2u);
+    tree.insert(Pt::new(50.0, 50.0), 3u);
+    tree.insert(Pt::new(90.0, 90.0), 4u);
+
+    let closest = tree.nearest(&Pt::new(0.0, 0.0), 2);
+    assert_eq!(closest.len(), 2);
+    assert_eq!(closest[0].x, 0.0);
+    assert_eq!(closest[1].x, 1.0);
+
+    let none = tree.nearest(&Pt::new(0.0, 0.0), 0);
+    assert_eq!(none.len(), 0);
+}
+
+#[test]
+fn testBounded() {
+    #[deriving(Show)]
+    impl QTNumber for f64 {}
+    // Anchored by its sw corner, but unlike `Pt` its `bounds()` covers a real
+    // area that can straddle more than one quadrant.
+    #[deriving(Clone, PartialEq)]
+    struct Rect { x: f64, y: f64, w: f64 }
+    impl Point<f64> for Rect {
+        fn x(&self)->f64{ self.x }
+        fn y(&self)->f64{ self.y }
+    }
+    impl Bounded<f64> for Rect {
+        fn bounds(&self) -> AABB<f64> { AABB::new(self.x, self.y, self.w) }
+    }
+    impl Rect {
+        fn new(x: f64, y: f64, w: f64) -> Rect {
+            Rect { x: x, y: y, w: w }
+        }
+    }
+
+    let mut tree:QuadTree<f64, Rect, uint> = QuadTree::new(AABB::new(0.0, 0.0, 100.0), 4);
+
+    // Straddles all four quadrants of the root (split at x=50, y=50), so it
+    // must land in the root's overflow list rather than any single child.
+    tree.insert(Rect::new(40.0, 40.0, 20.0), 0u);
+
+    let found = tree.query(&AABB::new(45.0, 45.0, 1.0));
+    assert_eq!(found.len(), 1);
+
+    let missed = tree.query(&AABB::new(90.0, 90.0, 5.0));
+    assert_eq!(missed.len(), 0);
+
+    // The rect lives in the root's overflow list, not any leaf, so `nearest`
+    // must consult `overflow` directly to find it.
+    let closest = tree.nearest(&Rect::new(45.0, 45.0, 0.0), 1);
+    assert_eq!(closest.len(), 1);
+    assert_eq!(closest[0].x, 40.0);
+
+    assert_eq!(tree.count(), 1);
+    assert!(tree.remove(&Rect::new(40.0, 40.0, 20.0)));
+    assert_eq!(tree.count(), 0);
+}
+
+#[test]
+fn testCursor() {
+    #[deriving(Show)]
+    impl QTNumber for f64 {}
+    #[deriving(Clone, PartialEq)]
+    struct Pt { x:f64, y: f64}
+    impl Point<f64> for Pt {
+        fn x(&self)->f64{ self.x }
+        fn y(&self)->f64{ self.y }
+    }
+    impl Bounded<f64> for Pt {
+        fn bounds(&self) -> AABB<f64> { AABB::new(self.x, self.y, 0.0) }
+    }
+    impl Pt {
+        fn new(x: f64, y: f64) -> Pt {
+            Pt { x: x, y: y }
+        }
+    }
+
+    let mut tree:QuadTree<f64, Pt, uint> = QuadTree::new(AABB::new(0.0, 0.0, 100.0), 4);
+
+    tree.insert(Pt::new(0.0, 0.0), 0u);
+    tree.insert(Pt::new(1.0, 1.0), 1u);
+
+    {
+        // `QuadTree::new` always builds the root as an already-split `Node`
+        // with four empty leaf children, so until removals collapse it the
+        // root has no `contents`; (0,0) and (1,1) both land in its sw quadrant.
+        let root = tree.root();
+        assert!(!root.is_leaf());
+        assert!(root.contents().is_none());
+
+        let children = root.children().unwrap();
+        assert!(children.sw.is_leaf());
+        assert_eq!(children.sw.contents().unwrap().len(), 2);
+    }
+
+    {
+        let mut cursor = tree.root_mut();
+        assert!(!cursor.is_leaf());
+
+        let children = cursor.child_indices().unwrap();
+        let mut sw = cursor.into_child(children.sw);
+        assert!(sw.is_leaf());
+        assert_eq!(sw.contents().unwrap().len(), 2);
+
+        sw.force_split();
+        assert!(!sw.is_leaf());
+
+        // Splitting sw (0,0,50) again puts (0,0) and (1,1) in its own sw
+        // quadrant (0,0,25); insert a point landing in its se quadrant
+        // (25,0,25) instead to exercise a fresh grandchild leaf.
+        let grandchildren = sw.child_indices().unwrap();
+        let mut sw_se = sw.into_child(grandchildren.se);
+        assert_eq!(sw_se.contents().unwrap().len(), 0);
+        assert!(sw_se.insert_here(Pt::new(30.0, 5.0), 2u));
+        assert_eq!(sw_se.contents().unwrap().len(), 1);
+    }
+
+    assert_eq!(tree.count(), 3);
+
+    {
+        let root = tree.root();
+        let children = root.children().unwrap();
+        let grandchildren = children.sw.children().unwrap();
+        assert_eq!(grandchildren.sw.contents().unwrap().len(), 2);
+        assert_eq!(grandchildren.se.contents().unwrap().len(), 1);
+    }
 }